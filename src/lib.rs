@@ -1,22 +1,117 @@
+#[cfg(not(any(feature = "dynamic", feature = "static")))]
+compile_error!("enable the `dynamic` feature (or, once implemented, `static`) to select how this crate links libopus");
+
+#[cfg(feature = "static")]
+compile_error!("the `static` (vendored libopus) linkage feature isn't implemented yet; enable `dynamic` instead");
+
 use std::borrow::Cow;
 
-use audio::Signal;
 use opus;
 use symphonia::core::{
-    audio::{AudioBuffer, AudioBufferRef, SignalSpec},
+    audio::{AudioBuffer, AudioBufferRef, Signal, SignalSpec},
     codecs::{CodecParameters, Decoder, DecoderOptions, CODEC_TYPE_OPUS},
     errors::*,
+    sample::Sample,
     *,
 };
 
+/// How `decode` should handle a gap between the previous packet's end and the current
+/// packet's timestamp. File playback never loses packets so the default is `Disabled`;
+/// real-time/streaming callers that can drop packets should opt in via
+/// [`SymphoniaDecoderLibOpus::set_packet_loss_handling`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketLossHandling {
+    /// Gaps in the timestamp sequence are ignored; a lost packet just produces a gap.
+    Disabled,
+    /// Synthesize the missing audio with libopus's packet-loss concealment (PLC), i.e.
+    /// `opus_decode` given a null/empty packet.
+    Concealment,
+    /// Reconstruct the missing audio from the in-band forward error correction (FEC) data
+    /// carried redundantly in the *next* received packet.
+    Fec,
+}
+
+impl Default for PacketLossHandling {
+    fn default() -> Self {
+        PacketLossHandling::Disabled
+    }
+}
+
+/// The sample format `decode` produces. Defaults to `S16` to match the historical behaviour
+/// of this decoder; consumers that want full-precision samples (DSP/mixing chains) can opt
+/// into `F32` via [`SymphoniaDecoderLibOpus::set_output_sample_format`] to skip the redundant
+/// integer<->float conversion they'd otherwise have to do themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputSampleFormat {
+    S16,
+    F32,
+}
+
+impl Default for OutputSampleFormat {
+    fn default() -> Self {
+        OutputSampleFormat::S16
+    }
+}
+
+/// Wraps either a plain libopus decoder (mono/stereo) or a multistream decoder (surround,
+/// built from coupled+uncoupled streams per the channel-mapping table), so the rest of the
+/// struct doesn't have to care which one it's talking to.
+enum LibOpusDecoder {
+    Single(opus::Decoder),
+    Multistream(opus::MSDecoder),
+}
+
+impl LibOpusDecoder {
+    fn decode(&mut self, input: &[u8], output: &mut [i16], fec: bool) -> opus::Result<usize> {
+        match self {
+            LibOpusDecoder::Single(d) => d.decode(input, output, fec),
+            LibOpusDecoder::Multistream(d) => d.decode(input, output, fec),
+        }
+    }
+
+    fn decode_float(&mut self, input: &[u8], output: &mut [f32], fec: bool) -> opus::Result<usize> {
+        match self {
+            LibOpusDecoder::Single(d) => d.decode_float(input, output, fec),
+            LibOpusDecoder::Multistream(d) => d.decode_float(input, output, fec),
+        }
+    }
+
+    fn reset_state(&mut self) -> opus::Result<()> {
+        match self {
+            LibOpusDecoder::Single(d) => d.reset_state(),
+            LibOpusDecoder::Multistream(d) => d.reset_state(),
+        }
+    }
+}
+
 /// Note that we only have to implement decoding, as .opus/.ogx files are actually
 /// ogg-containers with opus packets inside and ogg is already demuxed nicely :)
 pub struct SymphoniaDecoderLibOpus {
-    libopus_decoder: opus::Decoder, // This prevents the struct from being Sync.
-    libopus_output_buffer: [i16; 5760 * 2], // This struct is large. BUT, inst_func mallocs it.
+    libopus_decoder: LibOpusDecoder, // This prevents the struct from being Sync.
+    // The buffer cannot be smaller than 5760 frames, check libopus docs if in doubt! Sized for
+    // `channels` planes so multistream/surround output (>2 channels) fits alongside mono/stereo.
+    libopus_output_buffer: Vec<i16>,
+    libopus_output_buffer_f32: Vec<f32>, // Float counterpart, used when output_format is F32.
     decoded_buffer: AudioBuffer<i16>,
+    decoded_buffer_f32: AudioBuffer<f32>,
     params: CodecParameters,
     channels: usize,
+    // The rate libopus was configured to output at (one of `SUPPORTED_SAMPLE_RATES`), which
+    // may differ from the stream's 48 kHz granule-position timebase; used to rescale
+    // timestamp gaps (and, elsewhere, pre-skip/`n_frames`) from that timebase.
+    output_sample_rate: u32,
+    loss_handling: PacketLossHandling,
+    // The timestamp we expect the next packet passed to `decode` to start at, used to detect
+    // a gap (and thus a lost packet) when `loss_handling` is not `Disabled`.
+    expected_ts: Option<u64>,
+    output_format: OutputSampleFormat,
+    // Samples still to be discarded from the front of the decode for gapless playback, per
+    // the OpusHead pre-skip field (rescaled from 48 kHz to our configured output rate).
+    pre_skip: u64,
+    // Running count of samples emitted so far (after pre-skip trimming), used to detect and
+    // trim the padding libopus decodes past the container's reported total frame count.
+    samples_decoded: u64,
+    total_frames: Option<u64>,
 }
 
 // It is safe for different threads to have &SymphoniaDecoderLibOpus non-mutable references concurrently.
@@ -42,6 +137,151 @@ fn inst_func(params: &CodecParameters, options: &DecoderOptions) -> Result<Box<d
     }
 }
 
+/// Maps a libopus error onto the closest matching Symphonia error so that a single bad
+/// packet or unsupported request surfaces to the caller instead of unwinding the stack.
+fn map_opus_error(err: opus::Error) -> Error {
+    match err.code() {
+        opus::ErrorCode::BadArg => Error::DecodeError("libopus: bad argument"),
+        opus::ErrorCode::BufferTooSmall => Error::DecodeError("libopus: output buffer too small"),
+        opus::ErrorCode::InternalError => Error::DecodeError("libopus: internal decoder error"),
+        opus::ErrorCode::InvalidPacket => Error::DecodeError("libopus: invalid or corrupt packet"),
+        opus::ErrorCode::Unimplemented => Error::Unsupported("libopus: unimplemented operation"),
+        opus::ErrorCode::InvalidState => Error::DecodeError("libopus: decoder in an invalid state"),
+        opus::ErrorCode::AllocFail => Error::DecodeError("libopus: allocation failure"),
+        _ => Error::DecodeError("libopus: unknown decode error"),
+    }
+}
+
+/// Sample rates libopus decoders can be configured to output, regardless of the rate the
+/// stream was originally encoded at.
+const SUPPORTED_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+
+/// The largest number of frames a single Opus packet can decode to: 120 ms at 48 kHz, and so an
+/// upper bound at every lower configured output rate — libopus requires output buffers to be at
+/// least this large, and it won't decode (PLC/FEC included) more than this many frames in one call.
+const MAX_OPUS_FRAME_SIZE: usize = 5760;
+
+/// Reads the raw pre-skip field out of an OpusHead identification header (RFC 7845 section
+/// 5.1). This is always expressed in 48 kHz samples, regardless of the configured output rate.
+/// Returns 0 when there's no (or no usable) OpusHead, matching straight passthrough behaviour.
+fn parse_opus_pre_skip_48k(extra_data: Option<&[u8]>) -> u64 {
+    const MAGIC: &[u8] = b"OpusHead";
+    match extra_data {
+        Some(d) if d.len() >= 12 && &d[0..8] == MAGIC => u16::from_le_bytes([d[10], d[11]]) as u64,
+        _ => 0,
+    }
+}
+
+/// Rescales a sample count from the fixed 48 kHz Ogg-Opus granule-position timebase to
+/// `output_sample_rate`.
+fn rescale_from_48k(samples_48k: u64, output_sample_rate: u32) -> u64 {
+    (samples_48k * output_sample_rate as u64) / 48_000
+}
+
+/// Drops `pre_skip` leading samples (decrementing it as they're consumed) and, once the
+/// container's total frame count is known, trims any trailing padding libopus decoded past it
+/// so concatenated/looped Opus playback is sample-accurate and gapless.
+fn apply_gapless_trim<S: Sample>(
+    dbuf: &mut AudioBuffer<S>,
+    pre_skip: &mut u64,
+    samples_decoded: &mut u64,
+    total_frames: Option<u64>,
+) {
+    let frames = dbuf.frames() as u64;
+    let skip = (*pre_skip).min(frames);
+    *pre_skip -= skip;
+
+    let remaining = frames - skip;
+    let trim_end = match total_frames {
+        Some(total) if *samples_decoded + remaining > total => {
+            (*samples_decoded + remaining - total).min(remaining)
+        }
+        _ => 0,
+    };
+
+    dbuf.shift(skip as usize);
+    dbuf.truncate((remaining - trim_end) as usize);
+    *samples_decoded += remaining - trim_end;
+}
+
+fn validate_sample_rate(sample_rate: u32) -> errors::Result<u32> {
+    if SUPPORTED_SAMPLE_RATES.contains(&sample_rate) {
+        Ok(sample_rate)
+    } else {
+        Err(Error::Unsupported("libopus: unsupported output sample rate"))
+    }
+}
+
+/// Parses the stream count, coupled-stream count and the `channels`-entry per-channel mapping
+/// table out of an OpusHead identification header (RFC 7845 section 5.1), as needed to build a
+/// multistream decoder for surround content. Mapping family 0 (plain mono/stereo) isn't handled
+/// here since those channel counts use `LibOpusDecoder::Single` instead.
+fn parse_opus_channel_mapping(extra_data: &[u8], channels: usize) -> errors::Result<(u8, u8, Vec<u8>)> {
+    const MAGIC: &[u8] = b"OpusHead";
+    const MAPPING_FAMILY_OFFSET: usize = 18;
+    const MAPPING_TABLE_OFFSET: usize = 21;
+
+    if extra_data.len() <= MAPPING_FAMILY_OFFSET || &extra_data[0..8] != MAGIC {
+        return Err(Error::Unsupported(
+            "libopus: missing or malformed OpusHead needed for multistream channel mapping",
+        ));
+    }
+
+    let mapping_family = extra_data[MAPPING_FAMILY_OFFSET];
+    if mapping_family == 0 {
+        return Err(Error::Unsupported(
+            "libopus: channel mapping family 0 does not support more than 2 channels",
+        ));
+    }
+
+    if extra_data.len() < MAPPING_TABLE_OFFSET + channels {
+        return Err(Error::DecodeError(
+            "libopus: OpusHead truncated before channel mapping table",
+        ));
+    }
+
+    let stream_count = extra_data[MAPPING_TABLE_OFFSET - 2];
+    let coupled_stream_count = extra_data[MAPPING_TABLE_OFFSET - 1];
+    let mapping = extra_data[MAPPING_TABLE_OFFSET..MAPPING_TABLE_OFFSET + channels].to_vec();
+
+    Ok((stream_count, coupled_stream_count, mapping))
+}
+
+/// Fills the symphonia audio buffer with `frames` frames of decoded interleaved data from
+/// libopus, starting at `frame_offset` so concealment and real frames can sit side by side.
+/// TODO: could be a silly memcpy depending on the data layout of symphonia. Could potentially be optimized.
+fn copy_decoded_frames<S: Sample>(
+    dbuf: &mut AudioBuffer<S>,
+    src: &[S],
+    channels: usize,
+    frame_offset: usize,
+    frames: usize,
+) {
+    let mut planes = dbuf.planes_mut();
+    let mut ch = 0;
+    for plane in planes.planes() {
+        for s in 0..frames {
+            plane[frame_offset + s] = src[s * channels + ch];
+        }
+        ch += 1;
+    }
+}
+
+impl SymphoniaDecoderLibOpus {
+    /// Opt in to packet-loss handling for real-time/streaming use. File playback can leave
+    /// this at the default of `PacketLossHandling::Disabled` since packets there don't go
+    /// missing in transit.
+    pub fn set_packet_loss_handling(&mut self, mode: PacketLossHandling) {
+        self.loss_handling = mode;
+    }
+
+    /// Choose whether `decode` returns `AudioBufferRef::S16` (the default) or
+    /// `AudioBufferRef::F32`, decoded via libopus's float decode path.
+    pub fn set_output_sample_format(&mut self, format: OutputSampleFormat) {
+        self.output_format = format;
+    }
+}
+
 impl Decoder for SymphoniaDecoderLibOpus {
     fn try_new(
         params: &codecs::CodecParameters,
@@ -51,27 +291,78 @@ impl Decoder for SymphoniaDecoderLibOpus {
         Self: Sized,
     {
         // translate channels
-        let channels = match params.channels.unwrap().count() {
-            1 => opus::Channels::Mono,
-            2 => opus::Channels::Stereo,
-            // TODO: how to attach dynamic error data such as number of channels?
-            _ => return Err(Error::Unsupported("unsupported channel count")),
+        let channels_spec = params
+            .channels
+            .ok_or(Error::Unsupported("missing channel count"))?;
+        let channel_count = channels_spec.count();
+        let pre_skip_48k = parse_opus_pre_skip_48k(params.extra_data.as_deref());
+        let sample_rate = validate_sample_rate(
+            params
+                .sample_rate
+                .ok_or(Error::Unsupported("missing sample rate"))?,
+        )?;
+
+        let libopus_decoder = match channel_count {
+            1 => LibOpusDecoder::Single(
+                opus::Decoder::new(sample_rate, opus::Channels::Mono).map_err(map_opus_error)?,
+            ),
+            2 => LibOpusDecoder::Single(
+                opus::Decoder::new(sample_rate, opus::Channels::Stereo).map_err(map_opus_error)?,
+            ),
+            // Opus commonly carries 5.1/7.1 surround as coupled+uncoupled streams; build a
+            // multistream decoder from the channel-mapping table in the OpusHead extra data.
+            _ => {
+                let extra_data = params.extra_data.as_deref().ok_or(Error::Unsupported(
+                    "missing OpusHead extra data for multistream channel mapping",
+                ))?;
+                let (stream_count, coupled_stream_count, mapping) =
+                    parse_opus_channel_mapping(extra_data, channel_count)?;
+                // `MSDecoder::new` derives the channel count from `mapping.len()`, so unlike
+                // `opus::Decoder::new` it doesn't take an explicit channel count argument.
+                LibOpusDecoder::Multistream(
+                    opus::MSDecoder::new(sample_rate, stream_count, coupled_stream_count, &mapping)
+                        .map_err(map_opus_error)?,
+                )
+            }
         };
 
         // instantiate deocder and intermediate buffers
         Ok(SymphoniaDecoderLibOpus {
-            libopus_decoder: opus::Decoder::new(params.sample_rate.unwrap_or_default(), channels)
-                .unwrap(),
-            // The buffer cannot be smaller than this, check libopus docs if in doubt!
-            libopus_output_buffer: [0; 5760 * 2], // assume max channels for opus which is 2
+            libopus_decoder,
             // The buffer cannot be smaller than this, check libopus docs if in doubt!
+            libopus_output_buffer: vec![0; MAX_OPUS_FRAME_SIZE * channel_count],
+            libopus_output_buffer_f32: vec![0.0; MAX_OPUS_FRAME_SIZE * channel_count],
+            // Sized for *two* max-size Opus frames, not one: when loss handling is enabled a
+            // single `decode` call can render a concealed frame followed by the real frame, and
+            // both need to fit side by side. Use the decoder's configured output rate, not the
+            // stream's original encoding rate: Opus decoders can be asked to output at any of
+            // `SUPPORTED_SAMPLE_RATES` regardless of how the content was encoded, and
+            // `SignalSpec` must match what we actually emit.
             decoded_buffer: AudioBuffer::new(
-                5760, // frames
-                SignalSpec::new(48000, params.channels.unwrap()),
+                2 * MAX_OPUS_FRAME_SIZE,
+                SignalSpec::new(sample_rate, channels_spec),
+            ),
+            decoded_buffer_f32: AudioBuffer::new(
+                2 * MAX_OPUS_FRAME_SIZE,
+                SignalSpec::new(sample_rate, channels_spec),
             ),
             // Store this just to implement codec_params()
             params: params.clone(),
-            channels: params.channels.unwrap().count(),
+            channels: channel_count,
+            output_sample_rate: sample_rate,
+            loss_handling: PacketLossHandling::default(),
+            expected_ts: None,
+            output_format: OutputSampleFormat::default(),
+            pre_skip: rescale_from_48k(pre_skip_48k, sample_rate),
+            samples_decoded: 0,
+            // `CodecParameters::n_frames` mirrors the Ogg-Opus granule position, which per RFC
+            // 7845 section 4.2 counts samples from the very start of the decode — i.e. it's
+            // pre-skip-inclusive. `samples_decoded` only ever counts samples *after*
+            // pre-skip trimming, so subtract pre-skip here to compare like with like; otherwise
+            // `apply_gapless_trim` would chop `pre_skip` samples off the tail as well as the head.
+            total_frames: params
+                .n_frames
+                .map(|n| rescale_from_48k(n.saturating_sub(pre_skip_48k), sample_rate)),
         })
     }
 
@@ -84,7 +375,11 @@ impl Decoder for SymphoniaDecoderLibOpus {
 
     fn reset(&mut self) {
         // TODO: yea, this is 100% a guess!
-        self.libopus_decoder.reset_state().unwrap();
+        // `reset` has no way to report failure to the caller, so best-effort it: a failed
+        // reset leaves the decoder's internal state untouched rather than taking down the host.
+        let _ = self.libopus_decoder.reset_state();
+        // A reset (e.g. after a seek) invalidates whatever timestamp we were expecting next.
+        self.expected_ts = None;
     }
 
     fn codec_params(&self) -> &codecs::CodecParameters {
@@ -94,46 +389,185 @@ impl Decoder for SymphoniaDecoderLibOpus {
     }
 
     fn decode(&mut self, packet: &formats::Packet) -> errors::Result<audio::AudioBufferRef> {
-        // Decode some more data.
-        // TODO: forward error correction if used in situations where data can be lost.
-        let decoded = self
-            .libopus_decoder
-            .decode(&packet.data, &mut self.libopus_output_buffer[..], false)
-            .unwrap();
-        // TODO: detect end of file. How?
+        match self.output_format {
+            OutputSampleFormat::S16 => self.decode_s16(packet),
+            OutputSampleFormat::F32 => self.decode_f32(packet),
+        }
+    }
+
+    fn finalize(&mut self) -> codecs::FinalizeResult {
+        // TODO: is this correct? I think we're saying that we can't verify if it went ok.
+        codecs::FinalizeResult { verify_ok: None }
+    }
+
+    fn last_decoded(&self) -> audio::AudioBufferRef {
+        // WARNING: calling a self.libopus_decoder function with interior mutability would be unsafe!
+        // if called before we decode a frame, you get a buffer with length 0 (note capacity != length)
+        match self.output_format {
+            OutputSampleFormat::S16 => AudioBufferRef::S16(Cow::Borrowed(&self.decoded_buffer)),
+            OutputSampleFormat::F32 => AudioBufferRef::F32(Cow::Borrowed(&self.decoded_buffer_f32)),
+        }
+    }
+}
 
+impl SymphoniaDecoderLibOpus {
+    fn decode_s16(&mut self, packet: &formats::Packet) -> errors::Result<audio::AudioBufferRef> {
         // Clear out old data from symphonia intermediate buffer.
         let dbuf = &mut self.decoded_buffer;
         dbuf.clear();
-        dbuf.render_reserved(Some(decoded));
+        let mut frames_written = 0usize;
 
-        // Fill the symphonia audio buffer with decoded interleaved data from libopus.
-        // TODO: could be a silly memcpy depending on the data layout of symphonia. Could potentially be optimized.
-        {
-            let mut planes = dbuf.planes_mut();
-            let mut ch = 0;
-            for plane in planes.planes() {
-                let mut s = 0;
-                for sample in plane.iter_mut() {
-                    *sample = self.libopus_output_buffer[s * self.channels + ch];
-                    s += 1;
+        // If the caller opted in to loss handling and the incoming packet's timestamp jumps
+        // past what we expected to decode next, one or more packets were lost. Synthesize the
+        // gap first so the frames we emit below stay time-aligned with the stream.
+        if self.loss_handling != PacketLossHandling::Disabled {
+            if let Some(expected_ts) = self.expected_ts {
+                if packet.ts > expected_ts {
+                    // `packet.ts`/`expected_ts` are in the Ogg-Opus 48 kHz granule-position
+                    // timebase, not necessarily the rate we configured libopus to output at
+                    // (see chunk0-5), so rescale the gap before using it as a frame count.
+                    let lost_frames =
+                        rescale_from_48k(packet.ts - expected_ts, self.output_sample_rate);
+                    // PLC/FEC can only ever reconstruct a single missing Opus frame (libopus
+                    // itself rejects a larger `frame_size` with `BadArg`), so a gap wider than
+                    // that means our timestamp bookkeeping is off or loss handling is being
+                    // asked to paper over more than it can. Bail out rather than index past
+                    // `libopus_output_buffer` or overflow `decoded_buffer`'s capacity.
+                    if lost_frames > MAX_OPUS_FRAME_SIZE as u64 {
+                        return Err(Error::DecodeError(
+                            "libopus: timestamp gap is larger than a single Opus frame, can't conceal",
+                        ));
+                    }
+                    let lost_frames = lost_frames as usize;
+                    let out_len = lost_frames * self.channels;
+                    let concealed = match self.loss_handling {
+                        // Reconstruct the lost frame from the redundant FEC data embedded in
+                        // this (the *next* received) packet.
+                        PacketLossHandling::Fec => self
+                            .libopus_decoder
+                            .decode(&packet.data, &mut self.libopus_output_buffer[..out_len], true)
+                            .map_err(map_opus_error)?,
+                        // Pure concealment: ask libopus to mask the loss with no input packet.
+                        _ => self
+                            .libopus_decoder
+                            .decode(&[], &mut self.libopus_output_buffer[..out_len], false)
+                            .map_err(map_opus_error)?,
+                    };
+                    dbuf.render_reserved(Some(concealed));
+                    copy_decoded_frames(
+                        dbuf,
+                        &self.libopus_output_buffer,
+                        self.channels,
+                        frames_written,
+                        concealed,
+                    );
+                    frames_written += concealed;
                 }
-                ch += 1;
             }
         }
 
+        // Decode the packet we actually received.
+        // TODO: detect end of file. How?
+        let decoded = self
+            .libopus_decoder
+            .decode(&packet.data, &mut self.libopus_output_buffer[..], false)
+            .map_err(map_opus_error)?;
+        dbuf.render_reserved(Some(decoded));
+        copy_decoded_frames(
+            dbuf,
+            &self.libopus_output_buffer,
+            self.channels,
+            frames_written,
+            decoded,
+        );
+
+        self.expected_ts = Some(packet.ts + packet.dur);
+
+        apply_gapless_trim(
+            &mut self.decoded_buffer,
+            &mut self.pre_skip,
+            &mut self.samples_decoded,
+            self.total_frames,
+        );
+
         // Return a reference to what we just decoded.
         Ok(self.last_decoded())
     }
 
-    fn finalize(&mut self) -> codecs::FinalizeResult {
-        // TODO: is this correct? I think we're saying that we can't verify if it went ok.
-        codecs::FinalizeResult { verify_ok: None }
-    }
+    fn decode_f32(&mut self, packet: &formats::Packet) -> errors::Result<audio::AudioBufferRef> {
+        // Same flow as `decode_s16`, but via libopus's float decode path straight into an
+        // `AudioBuffer<f32>` so callers that want full-precision samples skip the redundant
+        // integer->float conversion.
+        let dbuf = &mut self.decoded_buffer_f32;
+        dbuf.clear();
+        let mut frames_written = 0usize;
 
-    fn last_decoded(&self) -> audio::AudioBufferRef {
-        // WARNING: calling a self.libopus_decoder function with interior mutability would be unsafe!
-        // if called before we decode a frame, you get a buffer with length 0 (note capacity != length)
-        AudioBufferRef::S16(Cow::Borrowed(&self.decoded_buffer))
+        if self.loss_handling != PacketLossHandling::Disabled {
+            if let Some(expected_ts) = self.expected_ts {
+                if packet.ts > expected_ts {
+                    // See the matching rescale in `decode_s16`: `packet.ts` is in the 48 kHz
+                    // granule-position timebase, which may not match our configured output rate.
+                    let lost_frames =
+                        rescale_from_48k(packet.ts - expected_ts, self.output_sample_rate);
+                    // See the matching check in `decode_s16`: a gap wider than a single Opus
+                    // frame can't be concealed in one call, so bail out instead of slicing
+                    // past `libopus_output_buffer_f32` or overflowing `decoded_buffer_f32`.
+                    if lost_frames > MAX_OPUS_FRAME_SIZE as u64 {
+                        return Err(Error::DecodeError(
+                            "libopus: timestamp gap is larger than a single Opus frame, can't conceal",
+                        ));
+                    }
+                    let lost_frames = lost_frames as usize;
+                    let out_len = lost_frames * self.channels;
+                    let concealed = match self.loss_handling {
+                        PacketLossHandling::Fec => self
+                            .libopus_decoder
+                            .decode_float(
+                                &packet.data,
+                                &mut self.libopus_output_buffer_f32[..out_len],
+                                true,
+                            )
+                            .map_err(map_opus_error)?,
+                        _ => self
+                            .libopus_decoder
+                            .decode_float(&[], &mut self.libopus_output_buffer_f32[..out_len], false)
+                            .map_err(map_opus_error)?,
+                    };
+                    dbuf.render_reserved(Some(concealed));
+                    copy_decoded_frames(
+                        dbuf,
+                        &self.libopus_output_buffer_f32,
+                        self.channels,
+                        frames_written,
+                        concealed,
+                    );
+                    frames_written += concealed;
+                }
+            }
+        }
+
+        let decoded = self
+            .libopus_decoder
+            .decode_float(&packet.data, &mut self.libopus_output_buffer_f32[..], false)
+            .map_err(map_opus_error)?;
+        dbuf.render_reserved(Some(decoded));
+        copy_decoded_frames(
+            dbuf,
+            &self.libopus_output_buffer_f32,
+            self.channels,
+            frames_written,
+            decoded,
+        );
+
+        self.expected_ts = Some(packet.ts + packet.dur);
+
+        apply_gapless_trim(
+            &mut self.decoded_buffer_f32,
+            &mut self.pre_skip,
+            &mut self.samples_decoded,
+            self.total_frames,
+        );
+
+        Ok(self.last_decoded())
     }
 }